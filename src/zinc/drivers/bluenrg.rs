@@ -16,18 +16,163 @@
 //! BlueNRG low-level SPI communication.
 // http://www.st.com/st-web-ui/static/active/en/resource/technical/document/user_manual/DM00114498.pdf
 
+use core::option::Option;
+use core::option::Option::{Some, None};
 use core::result::{Result, Ok, Err};
 use core::slice::SlicePrelude;
+#[cfg(feature = "spi-stats")]
+use core::cell::Cell;
 
 use hal::pin::Gpio;
 use hal::spi::Spi;
 
+/// Number of buckets in `SpiStats::transfer_bytes_histo`, each one a
+/// power-of-two upper bound on transfer size: <=1, <=2, <=4, ..., <=256,
+/// and a final overflow bucket for anything larger.
+#[cfg(feature = "spi-stats")]
+const HISTO_BUCKETS: usize = 10;
+
+/// Per-size SPI transfer statistics for a `BlueNrg` instance.
+///
+/// Compiles down to a zero-sized, zero-cost type unless the `spi-stats`
+/// feature is enabled.
+#[cfg(feature = "spi-stats")]
+pub struct SpiStats {
+  sends: Cell<u32>,
+  receives: Cell<u32>,
+  bytes_sent: Cell<u32>,
+  bytes_received: Cell<u32>,
+  sleeping_errors: Cell<u32>,
+  unknown_errors: Cell<u32>,
+  buffer_size_errors: Cell<u32>,
+  transfer_bytes_histo: [Cell<u32>; HISTO_BUCKETS],
+}
+
+#[cfg(not(feature = "spi-stats"))]
+pub struct SpiStats;
+
+#[cfg(feature = "spi-stats")]
+impl SpiStats {
+  fn new() -> SpiStats {
+    SpiStats {
+      sends: Cell::new(0),
+      receives: Cell::new(0),
+      bytes_sent: Cell::new(0),
+      bytes_received: Cell::new(0),
+      sleeping_errors: Cell::new(0),
+      unknown_errors: Cell::new(0),
+      buffer_size_errors: Cell::new(0),
+      transfer_bytes_histo: [
+        Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0),
+        Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0), Cell::new(0),
+      ],
+    }
+  }
+
+  fn record_send_call(&self) {
+    self.sends.set(self.sends.get() + 1);
+  }
+
+  fn record_receive_call(&self) {
+    self.receives.set(self.receives.get() + 1);
+  }
+
+  fn record_sent_chunk(&self, bytes: u16) {
+    self.bytes_sent.set(self.bytes_sent.get() + bytes as u32);
+    self.record_histo(bytes);
+  }
+
+  fn record_received_chunk(&self, bytes: u16) {
+    self.bytes_received.set(self.bytes_received.get() + bytes as u32);
+    self.record_histo(bytes);
+  }
+
+  fn record_error(&self, err: &SpiError) {
+    match *err {
+      SpiSleeping => self.sleeping_errors.set(self.sleeping_errors.get() + 1),
+      SpiUnknown(_) => self.unknown_errors.set(self.unknown_errors.get() + 1),
+      SpiBufferSize | SpiPayloadTooLarge(_) =>
+        self.buffer_size_errors.set(self.buffer_size_errors.get() + 1),
+    }
+  }
+
+  fn record_histo(&self, bytes: u16) {
+    let mut bucket = 0us;
+    let mut ceiling = 1u16;
+    while bytes > ceiling && bucket < self.transfer_bytes_histo.len() - 1 {
+      ceiling = ceiling << 1;
+      bucket += 1;
+    }
+    let cell = &self.transfer_bytes_histo[bucket];
+    cell.set(cell.get() + 1);
+  }
+
+  /// Number of `send` calls made, including ones that failed.
+  pub fn sends(&self) -> u32 { self.sends.get() }
+
+  /// Number of `receive` calls made, including ones that failed.
+  pub fn receives(&self) -> u32 { self.receives.get() }
+
+  /// Total bytes written across all `send` calls.
+  pub fn bytes_sent(&self) -> u32 { self.bytes_sent.get() }
+
+  /// Total bytes read across all `receive` calls.
+  pub fn bytes_received(&self) -> u32 { self.bytes_received.get() }
+
+  /// Number of `send`/`receive` calls that failed, by `SpiError` kind, in
+  /// `(sleeping, unknown, buffer_size)` order; `buffer_size` counts both
+  /// `SpiBufferSize` and `SpiPayloadTooLarge`.
+  pub fn errors(&self) -> (u32, u32, u32) {
+    (self.sleeping_errors.get(), self.unknown_errors.get(), self.buffer_size_errors.get())
+  }
+
+  /// Power-of-two bucketed histogram of per-frame transfer sizes, in
+  /// bytes: `[0]` is <=1 byte, `[1]` is <=2 bytes, ..., `[8]` is <=256
+  /// bytes, with the last bucket (`[9]`) catching everything larger.
+  pub fn transfer_bytes_histo(&self) -> &[Cell<u32>] {
+    &self.transfer_bytes_histo
+  }
+
+  fn reset(&self) {
+    self.sends.set(0);
+    self.receives.set(0);
+    self.bytes_sent.set(0);
+    self.bytes_received.set(0);
+    self.sleeping_errors.set(0);
+    self.unknown_errors.set(0);
+    self.buffer_size_errors.set(0);
+    for cell in self.transfer_bytes_histo.iter() {
+      cell.set(0);
+    }
+  }
+}
+
+#[cfg(not(feature = "spi-stats"))]
+impl SpiStats {
+  fn new() -> SpiStats { SpiStats }
+  fn record_send_call(&self) {}
+  fn record_receive_call(&self) {}
+  fn record_sent_chunk(&self, _bytes: u16) {}
+  fn record_received_chunk(&self, _bytes: u16) {}
+  fn record_error(&self, _err: &SpiError) {}
+  fn reset(&self) {}
+}
+
 #[repr(u8)]
 enum SpiControl {
   SpiWrite = 0x0A,
   SpiRead = 0x0B,
 }
 
+/// Number of times `send`/`receive` will re-poll the status frame while
+/// waiting for the device to wake up or to report a non-zero transfer
+/// window, before giving up.
+const DEFAULT_RETRIES: u32 = 100;
+
+/// Largest buffer `send`/`receive` will accept, since the wire-level
+/// window math is all done in `u16`.
+const MAX_PAYLOAD_SIZE: usize = 0xFFFF;
+
 /// Spi error codes.
 #[repr(u8)]
 pub enum SpiError {
@@ -35,26 +180,65 @@ pub enum SpiError {
   SpiSleeping,
   /// Status is unlnown.
   SpiUnknown(u8),
-  /// Given buffer is too large.
-  SpiBufferSize(u16),
+  /// Device reported a zero-size transfer window that didn't clear
+  /// after retrying, or the window was clamped to zero by the `Spi`
+  /// controller's limit or the caller-supplied cap.
+  SpiBufferSize,
+  /// Caller's buffer is longer than `MAX_PAYLOAD_SIZE`, which the `u16`
+  /// length and offset math in `send`/`receive` can express.
+  SpiPayloadTooLarge(usize),
 }
 
 /// BlueNRG driver.
 pub struct BlueNrg<G, S> {
   active: G,
-  //input: G,
-  //output: G,
+  irq: G,
   spi: S,
+  stats: SpiStats,
+  max_transfer: Option<u16>,
 }
 
 impl<G: Gpio, S: Spi> BlueNrg<G, S> {
-  /// Create a new BlueNRG driver instance.
-  pub fn new(active: G, spi: S) -> BlueNrg<G, S> {
+  /// Create a new BlueNRG driver instance. `irq` is the device's
+  /// data-ready line, which goes high once it has an event pending.
+  pub fn new(active: G, irq: G, spi: S) -> BlueNrg<G, S> {
     active.set_high();
     BlueNrg {
       active: active,
+      irq: irq,
       spi: spi,
+      stats: SpiStats::new(),
+      max_transfer: None,
+    }
+  }
+
+  /// Access the running SPI transfer statistics.
+  pub fn stats(&self) -> &SpiStats { &self.stats }
+
+  /// Reset every counter in `stats()` back to zero.
+  pub fn reset_stats(&self) { self.stats.reset() }
+
+  /// Cap every chunk of a `send`/`receive` transfer to at most this many
+  /// bytes, on top of whatever the device and the `Spi` controller
+  /// already allow. Pass `None` to remove the cap.
+  pub fn set_max_transfer_size(&mut self, cap: Option<u16>) {
+    self.max_transfer = cap;
+  }
+
+  /// The effective transfer window: the smallest of the device-reported
+  /// `window`, the controller's own `Spi::max_transfer_size`, and the
+  /// caller-supplied cap set via `set_max_transfer_size`.
+  fn clamp_window(&self, window: u16) -> u16 {
+    let mut window = window;
+    match self.spi.max_transfer_size() {
+      Some(limit) if limit < window => window = limit,
+      _ => {},
+    }
+    match self.max_transfer {
+      Some(cap) if cap < window => window = cap,
+      _ => {},
     }
+    window
   }
 
   /// Check device status and return the maximum write/read data sizes.
@@ -92,51 +276,274 @@ impl<G: Gpio, S: Spi> BlueNrg<G, S> {
     }
   }
 
-  /// Receive data into the given buffer.
+  /// Assert chip-select and read the 5-byte status frame for the given
+  /// direction, retrying while the device is asleep or its reported
+  /// window for that direction is still zero. On success, chip-select is
+  /// left asserted (low) with the device ready to shift the next frame.
+  fn transfer_header(&self, control: SpiControl, mut num_tries: u32) -> Result<u16, SpiError> {
+    loop {
+      self.active.set_low();
+      let status = self.spi.transfer(control as u8);
+      let w0 = self.spi.transfer(0);
+      let w1 = self.spi.transfer(0);
+      let r0 = self.spi.transfer(0);
+      let r1 = self.spi.transfer(0);
+      let window = match control {
+        SpiWrite => (w0 as u16 << 8) | (w1 as u16),
+        SpiRead => (r0 as u16 << 8) | (r1 as u16),
+      };
+
+      match status {
+        0x02 if window > 0 => return Ok(window),
+        0x02 | 0x00 | 0xFF if num_tries > 0 => {
+          self.active.set_high();
+          num_tries -= 1;
+        },
+        0x02 => {
+          self.active.set_high();
+          return Err(SpiBufferSize);
+        },
+        0x00 | 0xFF => {
+          self.active.set_high();
+          return Err(SpiSleeping);
+        },
+        other => {
+          self.active.set_high();
+          return Err(SpiUnknown(other));
+        },
+      }
+    }
+  }
+
+  /// Block until the IRQ line signals that the device has data ready,
+  /// instead of busy-polling `check()`.
+  pub fn wait_for_data(&self) {
+    while !self.irq.is_high() {}
+  }
+
+  /// Wait for the IRQ line, then receive data into the given buffer.
+  pub fn receive_when_ready(&self, buf: &mut [u8]) -> Result<(), SpiError> {
+    self.wait_for_data();
+    self.receive(buf)
+  }
+
+  /// Receive data into the given buffer, splitting the transfer into
+  /// device-window-sized chunks when the buffer is larger than the
+  /// device can shift out in one frame.
   pub fn receive(&self, buf: &mut [u8]) -> Result<(), SpiError> {
-    self.active.set_low();
-    let status = self.spi.transfer(SpiRead as u8);
-    self.spi.transfer(0);
-    self.spi.transfer(0);
-    let r0 = self.spi.transfer(0);
-    let r1 = self.spi.transfer(0);
-    let size = (r0 as u16 << 8) | (r1 as u16);
-    if status != 0x02 {
-      self.active.set_high();
-      Err(SpiUnknown(status))
-    }else if size < buf.len() as u16 {
-      self.active.set_high();
-      Err(SpiBufferSize(size))
-    }else {
-      for b in buf.iter_mut() {
-        *b = self.spi.transfer(0);
+    self.stats.record_receive_call();
+    if buf.len() > MAX_PAYLOAD_SIZE {
+      let e = SpiPayloadTooLarge(buf.len());
+      self.stats.record_error(&e);
+      return Err(e);
+    }
+    let len = buf.len() as u16;
+    let mut offset = 0u16;
+    while offset < len {
+      let window = match self.transfer_header(SpiRead, DEFAULT_RETRIES) {
+        Ok(window) => self.clamp_window(window),
+        Err(e) => {
+          self.stats.record_error(&e);
+          return Err(e);
+        },
+      };
+      if window == 0 {
+        self.active.set_high();
+        let e = SpiBufferSize;
+        self.stats.record_error(&e);
+        return Err(e);
       }
+      let remaining = len - offset;
+      let chunk = if remaining < window { remaining } else { window };
+      self.spi.read_buffer(buf.slice_mut(offset as usize, (offset + chunk) as usize));
       self.active.set_high();
-      Ok(())
+      self.stats.record_received_chunk(chunk);
+      offset += chunk;
     }
+    Ok(())
   }
 
-  /// Send data from the given buffer.
+  /// Send data from the given buffer, splitting the transfer into
+  /// device-window-sized chunks when the buffer is larger than the
+  /// device can accept in one frame.
   pub fn send(&self, buf: &[u8]) -> Result<(), SpiError> {
-    self.active.set_low();
-    let status = self.spi.transfer(SpiWrite as u8);
-    let w0 = self.spi.transfer(0);
-    let w1 = self.spi.transfer(0);
-    self.spi.transfer(0);
-    self.spi.transfer(0);
-    let size = (w0 as u16 << 8) | (w1 as u16);
-    if status != 0x02 {
-      self.active.set_high();
-      Err(SpiUnknown(status))
-    }else if size < buf.len() as u16 {
-      self.active.set_high();
-      Err(SpiBufferSize(size))
-    }else {
-      for b in buf.iter() {
-        self.spi.transfer(*b);
+    self.stats.record_send_call();
+    if buf.len() > MAX_PAYLOAD_SIZE {
+      let e = SpiPayloadTooLarge(buf.len());
+      self.stats.record_error(&e);
+      return Err(e);
+    }
+    let len = buf.len() as u16;
+    let mut offset = 0u16;
+    while offset < len {
+      let window = match self.transfer_header(SpiWrite, DEFAULT_RETRIES) {
+        Ok(window) => self.clamp_window(window),
+        Err(e) => {
+          self.stats.record_error(&e);
+          return Err(e);
+        },
+      };
+      if window == 0 {
+        self.active.set_high();
+        let e = SpiBufferSize;
+        self.stats.record_error(&e);
+        return Err(e);
       }
+      let remaining = len - offset;
+      let chunk = if remaining < window { remaining } else { window };
+      self.spi.write_buffer(buf.slice(offset as usize, (offset + chunk) as usize));
       self.active.set_high();
-      Ok(())
+      self.stats.record_sent_chunk(chunk);
+      offset += chunk;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use core::cell::Cell;
+  use core::option::Option;
+  use core::option::Option::{Some, None};
+  use core::result::{Ok, Err};
+
+  use hal::pin::Gpio;
+  use hal::spi::Spi;
+
+  use super::{BlueNrg, MAX_PAYLOAD_SIZE};
+  use super::SpiError::{SpiSleeping, SpiBufferSize, SpiPayloadTooLarge};
+
+  struct MockGpio {
+    high: Cell<bool>,
+  }
+
+  impl MockGpio {
+    fn new() -> MockGpio { MockGpio { high: Cell::new(true) } }
+  }
+
+  impl Gpio for MockGpio {
+    fn set_high(&self) { self.high.set(true); }
+    fn set_low(&self) { self.high.set(false); }
+    fn is_high(&self) -> bool { self.high.get() }
+  }
+
+  /// Fake `Spi` controller that answers every 5-byte status frame with a
+  /// fixed `status`/`window` pair, and counts bytes handed to
+  /// `write_buffer`/`read_buffer` instead of shifting them one at a time.
+  struct MockSpi {
+    status: u8,
+    window: u16,
+    max_transfer: Option<u16>,
+    header_calls: Cell<u32>,
+    bytes_transferred: Cell<usize>,
+  }
+
+  impl MockSpi {
+    fn new(status: u8, window: u16) -> MockSpi {
+      MockSpi {
+        status: status,
+        window: window,
+        max_transfer: None,
+        header_calls: Cell::new(0),
+        bytes_transferred: Cell::new(0),
+      }
+    }
+
+    fn with_max_transfer(status: u8, window: u16, max_transfer: u16) -> MockSpi {
+      MockSpi {
+        status: status,
+        window: window,
+        max_transfer: Some(max_transfer),
+        header_calls: Cell::new(0),
+        bytes_transferred: Cell::new(0),
+      }
+    }
+  }
+
+  impl Spi for MockSpi {
+    fn transfer(&self, _value: u8) -> u8 {
+      let idx = self.header_calls.get() % 5;
+      self.header_calls.set(self.header_calls.get() + 1);
+      match idx {
+        0 => self.status,
+        1 | 3 => (self.window >> 8) as u8,
+        2 | 4 => (self.window & 0xFF) as u8,
+        _ => 0,
+      }
+    }
+
+    fn max_transfer_size(&self) -> Option<u16> { self.max_transfer }
+
+    fn write_buffer(&self, buf: &[u8]) {
+      self.bytes_transferred.set(self.bytes_transferred.get() + buf.len());
+    }
+
+    fn read_buffer(&self, buf: &mut [u8]) {
+      self.bytes_transferred.set(self.bytes_transferred.get() + buf.len());
+    }
+  }
+
+  fn new_driver(spi: MockSpi) -> BlueNrg<MockGpio, MockSpi> {
+    BlueNrg::new(MockGpio::new(), MockGpio::new(), spi)
+  }
+
+  #[test]
+  fn send_bails_instead_of_spinning_when_clamped_window_is_zero() {
+    // Device reports a healthy 20-byte window, but the controller's own
+    // limit clamps it to zero: this must error out, not loop forever.
+    let driver = new_driver(MockSpi::with_max_transfer(0x02, 20, 0));
+    match driver.send(&[1, 2, 3]) {
+      Err(SpiBufferSize) => {},
+      _ => panic!("expected SpiBufferSize"),
+    }
+  }
+
+  #[test]
+  fn send_splits_buffer_larger_than_the_device_window() {
+    // window=4 over a 10-byte buffer: 4 + 4 + 2, exercising both the
+    // full-window and remaining-is-smaller-than-window branches.
+    let driver = new_driver(MockSpi::new(0x02, 4));
+    let buf = [0u8; 10];
+    assert!(driver.send(&buf).is_ok());
+    assert_eq!(driver.spi.bytes_transferred.get(), 10);
+    assert_eq!(driver.spi.header_calls.get(), 3 * 5);
+  }
+
+  #[test]
+  fn send_returns_sleeping_once_retries_are_exhausted() {
+    // Device never wakes up: transfer_header should retry and then
+    // surface SpiSleeping instead of hanging.
+    let driver = new_driver(MockSpi::new(0x00, 0));
+    match driver.send(&[1, 2, 3]) {
+      Err(SpiSleeping) => {},
+      _ => panic!("expected SpiSleeping"),
     }
   }
+
+  #[test]
+  fn send_accepts_a_buffer_exactly_at_the_max_payload_size() {
+    let driver = new_driver(MockSpi::new(0x02, 0xFFFF));
+    let buf = [0u8; MAX_PAYLOAD_SIZE];
+    assert!(driver.send(&buf).is_ok());
+  }
+
+  #[test]
+  fn send_rejects_a_buffer_one_byte_over_the_max_payload_size() {
+    let driver = new_driver(MockSpi::new(0x02, 0xFFFF));
+    let buf = [0u8; MAX_PAYLOAD_SIZE + 1];
+    match driver.send(&buf) {
+      Err(SpiPayloadTooLarge(size)) => assert_eq!(size, MAX_PAYLOAD_SIZE + 1),
+      _ => panic!("expected SpiPayloadTooLarge"),
+    }
+    // The oversized check must fire before any SPI activity.
+    assert_eq!(driver.spi.header_calls.get(), 0);
+  }
+
+  #[test]
+  fn receive_splits_buffer_larger_than_the_device_window() {
+    let driver = new_driver(MockSpi::new(0x02, 4));
+    let mut buf = [0u8; 10];
+    assert!(driver.receive(&mut buf).is_ok());
+    assert_eq!(driver.spi.bytes_transferred.get(), 10);
+  }
 }
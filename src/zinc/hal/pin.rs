@@ -0,0 +1,26 @@
+// Zinc, the bare metal stack for rust.
+// Copyright 2014 Dzmitry "kvark" Malyshau <kvarkus@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! GPIO pin abstraction.
+
+/// Generic digital I/O pin.
+pub trait Gpio {
+  /// Drive the pin high.
+  fn set_high(&self);
+  /// Drive the pin low.
+  fn set_low(&self);
+  /// Read the pin's current input level, `true` for high.
+  fn is_high(&self) -> bool;
+}
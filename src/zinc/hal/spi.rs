@@ -0,0 +1,55 @@
+// Zinc, the bare metal stack for rust.
+// Copyright 2014 Dzmitry "kvark" Malyshau <kvarkus@gmail.com>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! SPI peripheral abstraction.
+
+use core::option::Option;
+use core::option::Option::None;
+use core::slice::SlicePrelude;
+
+/// Generic SPI master.
+pub trait Spi {
+  /// Transfer a single byte, returning whatever was shifted in while
+  /// `value` was shifted out.
+  fn transfer(&self, value: u8) -> u8;
+
+  /// Largest single transfer this controller can issue, if it is
+  /// limited by FIFO depth or a DMA descriptor size.
+  ///
+  /// The default, `None`, means unlimited; backends with such a limit
+  /// should override this so callers can split transfers accordingly.
+  fn max_transfer_size(&self) -> Option<u16> { None }
+
+  /// Write a whole buffer out, discarding the bytes shifted in.
+  ///
+  /// The default implementation falls back to `transfer`, one byte at
+  /// a time. Backends with a FIFO or DMA engine should override this
+  /// to issue the whole buffer as a single burst.
+  fn write_buffer(&self, buf: &[u8]) {
+    for b in buf.iter() {
+      self.transfer(*b);
+    }
+  }
+
+  /// Fill a whole buffer by transferring zero bytes, keeping whatever
+  /// was shifted in.
+  ///
+  /// See `write_buffer` for the rationale behind overriding this.
+  fn read_buffer(&self, buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+      *b = self.transfer(0);
+    }
+  }
+}